@@ -9,14 +9,15 @@ use memoffset::offset_of;
 use page_collections::page_vec::PageVec;
 use riscv_page_tables::GuestStagePageTable;
 use riscv_pages::{GuestPhysAddr, PageOwnerId, PageSize, Pfn, RawAddr, SequentialPages};
-use riscv_regs::{hgatp, hstatus, scounteren, sstatus};
+use riscv_regs::{hgatp, hstatus, hvip, scounteren, sip, sstatus};
 use riscv_regs::{
-    Exception, GeneralPurposeRegisters, GprIndex, LocalRegisterCopy, Readable, Trap, Writeable, CSR,
+    Exception, GeneralPurposeRegisters, GprIndex, Interrupt, LocalRegisterCopy, Readable, Trap,
+    Writeable, CSR,
 };
 use sbi::{SbiMessage, SbiReturn};
 use spin::{Mutex, RwLock, RwLockReadGuard};
 
-use crate::vm::VmStateInitializing;
+use crate::vm::{VmStateFinalized, VmStateInitializing};
 use crate::vm_pages::VmPages;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -26,6 +27,24 @@ pub enum Error {
     VmCpuNotFound,
     VmCpuRunning,
     InsufficientVmCpuStorage,
+    /// Attempted to complete an MMIO load with no emulation pending.
+    NoPendingMmioLoad,
+    /// Failed to read the faulting instruction from guest memory, or to decode it (or the
+    /// `htinst` value standing in for it) as a load/store.
+    GuestInstructionFetchFailed,
+    /// Failed to read or write guest memory.
+    GuestMemoryAccessFailed,
+    /// Tried to `kick()` a vCPU that isn't currently running.
+    VcpuNotRunning,
+    /// Failed to send the IPI used to `kick()` a running vCPU.
+    KickFailed,
+    /// The faulting instruction had to be fetched from guest memory using `sepc` as a guest
+    /// physical address, but the guest has its own first-stage translation enabled (`vsatp` is
+    /// not in `Bare` mode), so `sepc` is a guest *virtual* address we have no way to translate.
+    GuestVirtAddrTranslationUnsupported,
+    /// An imported `VmCpuArchState` named an `interrupt_file` that doesn't correspond to a valid
+    /// `ImsicGuestId`.
+    InvalidImportedInterruptFile,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -48,7 +67,7 @@ struct HostCpuState {
 }
 
 /// Guest GPR and CSR state which must be saved/restored when exiting/entering virtualization.
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 #[repr(C)]
 struct GuestCpuState {
     gprs: GeneralPurposeRegisters,
@@ -60,10 +79,13 @@ struct GuestCpuState {
 
 /// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
 /// restored whenever we switch between VMs.
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 #[repr(C)]
 struct GuestVCpuState {
     hgatp: u64,
+    // Pending VS-level interrupts (vssip/vstip/vseip), set by `inject_interrupt()` and cleared by
+    // the guest acknowledging them.
+    hvip: u64,
     htimedelta: u64,
     vsstatus: u64,
     vsie: u64,
@@ -196,22 +218,427 @@ global_asm!(
     guest_sepc = const guest_csr_offset!(sepc),
 );
 
+/// The width of a guest MMIO load or store, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmioAccessWidth {
+    Byte,
+    HalfWord,
+    Word,
+    DoubleWord,
+}
+
+impl MmioAccessWidth {
+    /// Returns the width in bits.
+    fn bits(self) -> u32 {
+        match self {
+            Self::Byte => 8,
+            Self::HalfWord => 16,
+            Self::Word => 32,
+            Self::DoubleWord => 64,
+        }
+    }
+}
+
+/// The operation a trapping guest instruction was attempting to carry out.
+#[derive(Clone, Copy, Debug)]
+pub enum MmioOperation {
+    /// A load into `MmioAccess::gpr`.
+    Load,
+    /// A store of the value held in `MmioAccess::gpr` at the time of the fault.
+    Store(u64),
+}
+
+/// A decoded guest MMIO access, built from the trapping load/store instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct MmioAccess {
+    /// Whether this is a load or a store, and the store value if applicable.
+    pub op: MmioOperation,
+    /// The width of the access.
+    pub width: MmioAccessWidth,
+    /// Whether a loaded value should be sign-extended when written back to the GPR.
+    pub sign_extend: bool,
+    /// The GPR that is the source (store) or destination (load) of the access.
+    pub gpr: GprIndex,
+}
+
+/// Tracks an in-flight MMIO load so that `complete_mmio_load()` can finish it off.
+struct PendingMmioLoad {
+    gpr: GprIndex,
+    width: MmioAccessWidth,
+    sign_extend: bool,
+    // Advance `sepc` by 2 instead of 4 if the faulting instruction was compressed.
+    compressed: bool,
+}
+
+/// Guest-visible CSRs that an external debug agent can inspect or modify on a paused vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugCsr {
+    Sepc,
+    Sstatus,
+    Hstatus,
+    Scounteren,
+    Hgatp,
+    Vsstatus,
+    Vsie,
+    Vstvec,
+    Vsscratch,
+    Vsepc,
+    Vscause,
+    Vstval,
+    Vsatp,
+}
+
+// Trigger-module constants used to arm a one-shot "icount" trigger for single-stepping. See the
+// RISC-V Debug Specification for the `tdata1`/`tselect` layout.
+const SINGLE_STEP_TRIGGER: u64 = 0;
+const TDATA1_TYPE_ICOUNT: u64 = 3;
+const TDATA1_TYPE_SHIFT: u64 = 60;
+const TDATA1_ICOUNT_VS: u64 = 1 << 26;
+const TDATA1_ICOUNT_COUNT_SHIFT: u64 = 10;
+
+/// A VS-level virtual interrupt source that can be injected into a guest with
+/// `VmCpu::inject_interrupt()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtualInterrupt {
+    /// VS-level software interrupt (`vssip`).
+    Software,
+    /// VS-level timer interrupt (`vstip`).
+    Timer,
+    /// VS-level external interrupt (`vseip`).
+    External,
+}
+
+// Sentinel `interrupt_file` value meaning "no interrupt file assigned", since `VmCpuArchState`
+// has to stay POD rather than storing an `Option<ImsicGuestId>` directly.
+const NO_INTERRUPT_FILE: u32 = u32::MAX;
+
+/// A flattened, fixed-layout snapshot of a `VmCpu`'s architectural state, suitable for saving and
+/// later restoring a stopped guest (e.g. for suspend/resume or migration).
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct VmCpuArchState {
+    guest_regs: GuestCpuState,
+    guest_vcpu_csrs: GuestVCpuState,
+    interrupt_file: u32,
+}
+
+/// Number of entries in the RISC-V general register file, x0-x31.
+const NUM_GPRS: usize = 32;
+
+/// ELF64 `NT_PRSTATUS` note payload for a single vCPU: the GPR file plus `sepc`, which stands in
+/// for the program counter that a non-virtualized `NT_PRSTATUS` would carry.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct PrStatus {
+    pub gprs: [u64; NUM_GPRS],
+    pub sepc: u64,
+}
+
+/// A region of guest physical memory to include as a `PT_LOAD` segment in a VM core dump.
+#[derive(Clone, Copy)]
+pub struct CoreDumpRegion {
+    pub addr: GuestPhysAddr,
+    pub owner: PageOwnerId,
+    pub len: u64,
+}
+
 /// Identifies the exit cause for a vCPU.
 pub enum VmCpuExit {
     /// ECALLs from VS mode.
     Ecall(Option<SbiMessage>),
-    /// G-stage page faults.
+    /// A guest load/store to a GPA that isn't backed by guest memory, decoded into the access
+    /// the host must emulate.
+    Mmio {
+        addr: GuestPhysAddr,
+        access: MmioAccess,
+    },
+    /// G-stage instruction fetch page faults. G-stage load/store faults are reported as `Mmio`
+    /// above, or as `Other` if the faulting instruction can't be decoded.
     PageFault(GuestPhysAddr),
+    /// The guest hit a software breakpoint (`ebreak`) or completed an armed single step.
+    DebugTrap { pc: u64 },
+    /// The guest executed `wfi` with nothing pending. The host should block the physical CPU
+    /// until an interrupt is injected rather than spin re-entering the guest.
+    Wfi,
+    /// The physical CPU running this vCPU was kicked via `VmCpus::kick()`. The scheduler should
+    /// re-check for pending work (newly-injected interrupts, stop/migration requests, etc.) and
+    /// re-enter the guest if there's nothing else to do.
+    Interrupted,
     /// Everything else that we currently don't or can't handle.
     Other(VmCpuTrapState),
     // TODO: Add other exit causes as needed.
 }
 
+// Bits of a RISC-V load/store instruction (and of the `htinst`-transformed pseudo-instruction,
+// which shares the same encoding) that we need in order to decode an MMIO access.
+const INSN_OPCODE_MASK: u32 = 0x7f;
+const INSN_OPCODE_LOAD: u32 = 0x03;
+const INSN_OPCODE_STORE: u32 = 0x23;
+const INSN_FUNCT3_SHIFT: u32 = 12;
+const INSN_FUNCT3_MASK: u32 = 0x7;
+const INSN_RD_SHIFT: u32 = 7;
+const INSN_RS_MASK: u32 = 0x1f;
+const INSN_RS2_SHIFT: u32 = 20;
+
+// The full 32-bit encoding of `wfi` (the only instruction `hstatus.VTW` causes to trap as a
+// `VirtualInstruction` exception today; `hcounteren`-gated counter reads are another possible
+// cause that we don't currently configure, see the `VirtualInstruction` arm of `run_to_exit`).
+const INSN_WFI: u32 = 0x1050_0073;
+
+enum DecodedInsn {
+    Load {
+        gpr: GprIndex,
+        width: MmioAccessWidth,
+        sign_extend: bool,
+    },
+    Store {
+        gpr: GprIndex,
+        width: MmioAccessWidth,
+    },
+}
+
+/// Decodes the load/store `funct3`/`rd`/`rs2` fields of `insn`, which must be either a real
+/// 32-bit instruction fetched from guest memory or the transformed pseudo-instruction provided
+/// by `htinst`.
+fn decode_load_store_insn(insn: u32) -> Option<DecodedInsn> {
+    let opcode = insn & INSN_OPCODE_MASK;
+    let funct3 = (insn >> INSN_FUNCT3_SHIFT) & INSN_FUNCT3_MASK;
+    let (width, sign_extend) = match funct3 {
+        0b000 => (MmioAccessWidth::Byte, true),
+        0b001 => (MmioAccessWidth::HalfWord, true),
+        0b010 => (MmioAccessWidth::Word, true),
+        0b011 => (MmioAccessWidth::DoubleWord, false),
+        0b100 => (MmioAccessWidth::Byte, false),
+        0b101 => (MmioAccessWidth::HalfWord, false),
+        0b110 => (MmioAccessWidth::Word, false),
+        _ => return None,
+    };
+    match opcode {
+        INSN_OPCODE_LOAD => {
+            let rd = (insn >> INSN_RD_SHIFT) & INSN_RS_MASK;
+            Some(DecodedInsn::Load {
+                gpr: GprIndex::from_raw(rd)?,
+                width,
+                sign_extend,
+            })
+        }
+        INSN_OPCODE_STORE => {
+            let rs2 = (insn >> INSN_RS2_SHIFT) & INSN_RS_MASK;
+            Some(DecodedInsn::Store {
+                gpr: GprIndex::from_raw(rs2)?,
+                width,
+            })
+        }
+        _ => None,
+    }
+}
+
+// Bits of a quadrant-0 RVC load/store instruction needed to decode an MMIO access. `c.lw`/`c.ld`/
+// `c.sw`/`c.sd` are the only compressed loads/stores that can target MMIO (the floating-point
+// forms `c.fld`/`c.fsd`/`c.flw`/`c.fsw` can't, since MMIO devices are never accessed with FP
+// loads/stores).
+const INSN_C_OPCODE_MASK: u16 = 0x3;
+const INSN_C_OPCODE_QUADRANT0: u16 = 0x0;
+const INSN_C_FUNCT3_SHIFT: u16 = 13;
+const INSN_C_FUNCT3_MASK: u16 = 0x7;
+const INSN_C_RDRS2_SHIFT: u16 = 2;
+const INSN_C_RDRS2_MASK: u16 = 0x7;
+// `c.lw`/`c.sw`/`c.ld`/`c.sd` only reach the compressed 8 "popular" registers (x8-x15), encoded
+// as a 3-bit field biased by this amount.
+const INSN_C_REG_BIAS: u32 = 8;
+
+/// Decodes the load/store `funct3`/`rd'`/`rs2'` fields of the quadrant-0 compressed instruction
+/// `insn`, which must have been fetched from guest memory (`htinst` always holds a standard
+/// 32-bit encoding, even for a compressed original).
+fn decode_compressed_load_store_insn(insn: u16) -> Option<DecodedInsn> {
+    if insn & INSN_C_OPCODE_MASK != INSN_C_OPCODE_QUADRANT0 {
+        return None;
+    }
+    let funct3 = (insn >> INSN_C_FUNCT3_SHIFT) & INSN_C_FUNCT3_MASK;
+    let reg = GprIndex::from_raw(
+        (((insn >> INSN_C_RDRS2_SHIFT) & INSN_C_RDRS2_MASK) as u32) + INSN_C_REG_BIAS,
+    )?;
+    match funct3 {
+        // c.lw
+        0b010 => Some(DecodedInsn::Load {
+            gpr: reg,
+            width: MmioAccessWidth::Word,
+            sign_extend: true,
+        }),
+        // c.ld (RV64)
+        0b011 => Some(DecodedInsn::Load {
+            gpr: reg,
+            width: MmioAccessWidth::DoubleWord,
+            sign_extend: false,
+        }),
+        // c.sw
+        0b110 => Some(DecodedInsn::Store {
+            gpr: reg,
+            width: MmioAccessWidth::Word,
+        }),
+        // c.sd (RV64)
+        0b111 => Some(DecodedInsn::Store {
+            gpr: reg,
+            width: MmioAccessWidth::DoubleWord,
+        }),
+        _ => None,
+    }
+}
+
+/// Sign- or zero-extends `value`, which holds `width.bits()` meaningful low-order bits.
+fn extend_mmio_value(value: u64, width: MmioAccessWidth, sign_extend: bool) -> u64 {
+    let bits = width.bits();
+    if bits == 64 {
+        return value;
+    }
+    let mask = (1u64 << bits) - 1;
+    let truncated = value & mask;
+    if sign_extend && (truncated & (1 << (bits - 1))) != 0 {
+        truncated | !mask
+    } else {
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod decode_tests {
+    use super::*;
+
+    #[test]
+    fn decode_lw_and_sw() {
+        // lw a0, 0(a1): opcode 0x03, funct3 010, rd=a0(10), rs1=a1(11), imm=0.
+        let insn = (10u32 << INSN_RD_SHIFT) | (0b010 << INSN_FUNCT3_SHIFT) | (11 << 15) | 0x03;
+        match decode_load_store_insn(insn) {
+            Some(DecodedInsn::Load {
+                gpr,
+                width,
+                sign_extend,
+            }) => {
+                assert_eq!(gpr, GprIndex::A0);
+                assert_eq!(width, MmioAccessWidth::Word);
+                assert!(sign_extend);
+            }
+            _ => panic!("expected a decoded load"),
+        }
+
+        // sw a0, 0(a1): opcode 0x23, funct3 010, rs1=a1(11), rs2=a0(10).
+        let insn = (10u32 << INSN_RS2_SHIFT) | (0b010 << INSN_FUNCT3_SHIFT) | (11 << 15) | 0x23;
+        match decode_load_store_insn(insn) {
+            Some(DecodedInsn::Store { gpr, width }) => {
+                assert_eq!(gpr, GprIndex::A0);
+                assert_eq!(width, MmioAccessWidth::Word);
+            }
+            _ => panic!("expected a decoded store"),
+        }
+    }
+
+    #[test]
+    fn decode_rejects_non_load_store_opcode() {
+        // A branch instruction (opcode 0x63) isn't a load or store.
+        assert!(decode_load_store_insn(0x63).is_none());
+    }
+
+    #[test]
+    fn htinst_compressed_bit_does_not_mean_rvc_encoding() {
+        // lw a0, 0(a1): standard 32-bit opcode 0x03, whose low two bits are 0b11. `htinst`'s
+        // compressed flag is just bit 1 of the word (clear for a compressed original, set here
+        // as part of the normal LOAD opcode) -- in neither case are the low 16 bits a genuine
+        // RVC encoding, and `decode_mmio_fault` must not route `htinst` through
+        // `decode_compressed_load_store_insn` on that basis.
+        let insn = (10u32 << INSN_RD_SHIFT) | (0b010 << INSN_FUNCT3_SHIFT) | (11 << 15) | 0x03;
+        assert_ne!(insn & 0x2, 0);
+
+        match decode_load_store_insn(insn) {
+            Some(DecodedInsn::Load { gpr, width, .. }) => {
+                assert_eq!(gpr, GprIndex::A0);
+                assert_eq!(width, MmioAccessWidth::Word);
+            }
+            _ => panic!("expected a decoded load via the standard decoder"),
+        }
+
+        // The RVC decoder requires quadrant 0 (bits[1:0] == 0b00) and must reject this
+        // 0b11-quadrant instruction outright.
+        assert!(decode_compressed_load_store_insn(insn as u16).is_none());
+    }
+
+    #[test]
+    fn decode_compressed_lw_ld_sw_sd() {
+        // c.lw a0(rd'=2 -> x10), funct3 010, quadrant 00.
+        let insn: u16 = (0b010 << INSN_C_FUNCT3_SHIFT) | (2 << INSN_C_RDRS2_SHIFT);
+        match decode_compressed_load_store_insn(insn) {
+            Some(DecodedInsn::Load {
+                gpr,
+                width,
+                sign_extend,
+            }) => {
+                assert_eq!(gpr, GprIndex::A0);
+                assert_eq!(width, MmioAccessWidth::Word);
+                assert!(sign_extend);
+            }
+            _ => panic!("expected a decoded compressed load"),
+        }
+
+        // c.ld a0, funct3 011.
+        let insn: u16 = (0b011 << INSN_C_FUNCT3_SHIFT) | (2 << INSN_C_RDRS2_SHIFT);
+        match decode_compressed_load_store_insn(insn) {
+            Some(DecodedInsn::Load { width, .. }) => {
+                assert_eq!(width, MmioAccessWidth::DoubleWord);
+            }
+            _ => panic!("expected a decoded compressed load"),
+        }
+
+        // c.sw a0, funct3 110.
+        let insn: u16 = (0b110 << INSN_C_FUNCT3_SHIFT) | (2 << INSN_C_RDRS2_SHIFT);
+        match decode_compressed_load_store_insn(insn) {
+            Some(DecodedInsn::Store { gpr, width }) => {
+                assert_eq!(gpr, GprIndex::A0);
+                assert_eq!(width, MmioAccessWidth::Word);
+            }
+            _ => panic!("expected a decoded compressed store"),
+        }
+
+        // c.sd a0, funct3 111.
+        let insn: u16 = (0b111 << INSN_C_FUNCT3_SHIFT) | (2 << INSN_C_RDRS2_SHIFT);
+        match decode_compressed_load_store_insn(insn) {
+            Some(DecodedInsn::Store { width, .. }) => {
+                assert_eq!(width, MmioAccessWidth::DoubleWord);
+            }
+            _ => panic!("expected a decoded compressed store"),
+        }
+    }
+
+    #[test]
+    fn decode_compressed_rejects_other_quadrants() {
+        // Quadrant 10 (e.g. c.swsp-family) isn't handled by this decoder.
+        let insn: u16 = (0b110 << INSN_C_FUNCT3_SHIFT) | (2 << INSN_C_RDRS2_SHIFT) | 0b10;
+        assert!(decode_compressed_load_store_insn(insn).is_none());
+    }
+
+    #[test]
+    fn extend_sign_and_zero() {
+        assert_eq!(
+            extend_mmio_value(0xff, MmioAccessWidth::Byte, true),
+            0xffff_ffff_ffff_ffff
+        );
+        assert_eq!(extend_mmio_value(0xff, MmioAccessWidth::Byte, false), 0xff);
+        assert_eq!(
+            extend_mmio_value(0x8000, MmioAccessWidth::HalfWord, true),
+            0xffff_ffff_ffff_8000
+        );
+        assert_eq!(
+            extend_mmio_value(0xffff_ffff, MmioAccessWidth::Word, false),
+            0xffff_ffff
+        );
+    }
+}
+
 /// Represents a single virtual CPU of a VM.
 pub struct VmCpu {
     state: VmCpuState,
     interrupt_file: Option<ImsicGuestId>,
     guest_id: PageOwnerId,
+    pending_mmio_load: Option<PendingMmioLoad>,
+    single_step: bool,
 }
 
 impl VmCpu {
@@ -222,6 +649,7 @@ impl VmCpu {
         let mut hstatus = LocalRegisterCopy::<u64, hstatus::Register>::new(0);
         hstatus.modify(hstatus::spv.val(1));
         hstatus.modify(hstatus::spvp::Supervisor);
+        hstatus.modify(hstatus::vtw.val(1)); // Trap `wfi` from VS-mode instead of letting it block.
         state.guest_regs.hstatus = hstatus.get();
 
         let mut sstatus = LocalRegisterCopy::<u64, sstatus::Register>::new(0);
@@ -239,6 +667,8 @@ impl VmCpu {
             state,
             interrupt_file: None,
             guest_id,
+            pending_mmio_load: None,
+            single_step: false,
         }
     }
 
@@ -264,6 +694,109 @@ impl VmCpu {
         self.state.guest_regs.gprs.set_reg(gpr, value);
     }
 
+    /// Gets the current value of one of the vCPU's general-purpose registers.
+    pub fn get_gpr(&self, gpr: GprIndex) -> u64 {
+        self.state.guest_regs.gprs.reg(gpr)
+    }
+
+    /// Reads one of the guest-visible CSRs tracked by this vCPU.
+    pub fn read_csr(&self, csr: DebugCsr) -> u64 {
+        use DebugCsr::*;
+        match csr {
+            Sepc => self.state.guest_regs.sepc,
+            Sstatus => self.state.guest_regs.sstatus,
+            Hstatus => self.state.guest_regs.hstatus,
+            Scounteren => self.state.guest_regs.scounteren,
+            Hgatp => self.state.guest_vcpu_csrs.hgatp,
+            Vsstatus => self.state.guest_vcpu_csrs.vsstatus,
+            Vsie => self.state.guest_vcpu_csrs.vsie,
+            Vstvec => self.state.guest_vcpu_csrs.vstvec,
+            Vsscratch => self.state.guest_vcpu_csrs.vsscratch,
+            Vsepc => self.state.guest_vcpu_csrs.vsepc,
+            Vscause => self.state.guest_vcpu_csrs.vscause,
+            Vstval => self.state.guest_vcpu_csrs.vstval,
+            Vsatp => self.state.guest_vcpu_csrs.vsatp,
+        }
+    }
+
+    /// Writes one of the guest-visible CSRs tracked by this vCPU.
+    pub fn write_csr(&mut self, csr: DebugCsr, value: u64) {
+        use DebugCsr::*;
+        match csr {
+            Sepc => self.state.guest_regs.sepc = value,
+            Sstatus => self.state.guest_regs.sstatus = value,
+            Hstatus => self.state.guest_regs.hstatus = value,
+            Scounteren => self.state.guest_regs.scounteren = value,
+            Hgatp => self.state.guest_vcpu_csrs.hgatp = value,
+            Vsstatus => self.state.guest_vcpu_csrs.vsstatus = value,
+            Vsie => self.state.guest_vcpu_csrs.vsie = value,
+            Vstvec => self.state.guest_vcpu_csrs.vstvec = value,
+            Vsscratch => self.state.guest_vcpu_csrs.vsscratch = value,
+            Vsepc => self.state.guest_vcpu_csrs.vsepc = value,
+            Vscause => self.state.guest_vcpu_csrs.vscause = value,
+            Vstval => self.state.guest_vcpu_csrs.vstval = value,
+            Vsatp => self.state.guest_vcpu_csrs.vsatp = value,
+        }
+    }
+
+    /// Copies `buf.len()` bytes of guest memory starting at `addr` into `buf`.
+    pub fn read_guest_memory<T: GuestStagePageTable>(
+        &self,
+        vm_pages: &VmPages<T, VmStateFinalized>,
+        addr: GuestPhysAddr,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        vm_pages
+            .copy_from_guest(addr, buf)
+            .map_err(|_| Error::GuestMemoryAccessFailed)
+    }
+
+    /// Copies `buf` into guest memory starting at `addr`.
+    pub fn write_guest_memory<T: GuestStagePageTable>(
+        &self,
+        vm_pages: &VmPages<T, VmStateFinalized>,
+        addr: GuestPhysAddr,
+        buf: &[u8],
+    ) -> Result<()> {
+        vm_pages
+            .copy_to_guest(addr, buf)
+            .map_err(|_| Error::GuestMemoryAccessFailed)
+    }
+
+    /// Arms (`enable == true`) or disarms hardware single-stepping of the guest. While armed,
+    /// the next VS-level instruction the guest retires raises a trigger-module breakpoint
+    /// exception, which `run_to_exit` reports as `VmCpuExit::DebugTrap` instead of letting the
+    /// guest continue.
+    ///
+    /// This only takes effect the next time the vCPU is run; `run_to_exit` always disarms the
+    /// hardware trigger before returning (see `disarm_single_step_trigger()`), so there's no
+    /// hardware state to touch here even if single-stepping is being turned off.
+    pub fn enable_single_step(&mut self, enable: bool) {
+        self.single_step = enable;
+    }
+
+    /// Programs trigger 0 as a one-shot VS-mode `icount` trigger so the guest traps back to us
+    /// after retiring exactly one more instruction.
+    fn arm_single_step_trigger(&self) {
+        CSR.tselect.set(SINGLE_STEP_TRIGGER);
+        let tdata1 = (TDATA1_TYPE_ICOUNT << TDATA1_TYPE_SHIFT)
+            | TDATA1_ICOUNT_VS
+            | (1 << TDATA1_ICOUNT_COUNT_SHIFT);
+        CSR.tdata1.set(tdata1);
+    }
+
+    /// Clears trigger 0, undoing `arm_single_step_trigger()`. The trigger CSRs are a physical,
+    /// hart-global resource rather than per-vCPU architectural state, so unlike the guest CSRs
+    /// saved/restored around `_run_guest` above, there's nothing to preserve here: if the trigger
+    /// didn't fire (e.g. the guest took an `Ecall` or `Mmio` exit before retiring the stepped
+    /// instruction), it must still be disarmed before this hart can safely run any other vCPU, or
+    /// a trigger armed for this vCPU could spuriously fire a `Breakpoint` against an unrelated one
+    /// later scheduled on the same hart.
+    fn disarm_single_step_trigger(&self) {
+        CSR.tselect.set(SINGLE_STEP_TRIGGER);
+        CSR.tdata1.set(0);
+    }
+
     /// Updates A0/A1 with the result of an SBI call.
     pub fn set_ecall_result(&mut self, result: SbiReturn) {
         self.set_gpr(GprIndex::A0, result.error_code as u64);
@@ -283,10 +816,196 @@ impl VmCpu {
         self.state.guest_regs.hstatus = hstatus.get();
     }
 
+    /// Requests delivery of `irq` to the guest. The pending bit is OR'd into `hvip` and takes
+    /// effect the next time this vCPU is run. A software interrupt is cleared when the guest
+    /// clears its `sip.SSIP`, which aliases `hvip.VSSIP` and is saved back on exit; nothing in
+    /// this vCPU clears an injected timer or external interrupt bit.
+    pub fn inject_interrupt(&mut self, irq: VirtualInterrupt) {
+        let mut hvip =
+            LocalRegisterCopy::<u64, hvip::Register>::new(self.state.guest_vcpu_csrs.hvip);
+        match irq {
+            VirtualInterrupt::Software => hvip.modify(hvip::vssip.val(1)),
+            VirtualInterrupt::Timer => hvip.modify(hvip::vstip.val(1)),
+            VirtualInterrupt::External => hvip.modify(hvip::vseip.val(1)),
+        }
+        self.state.guest_vcpu_csrs.hvip = hvip.get();
+    }
+
+    /// Serializes this vCPU's register file into an ELF64 `NT_PRSTATUS` note payload, for
+    /// inclusion in a post-mortem core dump of the guest.
+    pub fn prstatus_note(&self) -> PrStatus {
+        let mut gprs = [0u64; NUM_GPRS];
+        // x0 is hardwired to zero and isn't tracked in `GeneralPurposeRegisters`.
+        for (i, slot) in gprs.iter_mut().enumerate().skip(1) {
+            if let Some(index) = GprIndex::from_raw(i as u32) {
+                *slot = self.get_gpr(index);
+            }
+        }
+        PrStatus {
+            gprs,
+            sepc: self.state.guest_regs.sepc,
+        }
+    }
+
+    /// Captures a snapshot of this vCPU's complete architectural state, for later restoration
+    /// via `import_state()`.
+    pub fn export_state(&self) -> VmCpuArchState {
+        VmCpuArchState {
+            guest_regs: self.state.guest_regs,
+            guest_vcpu_csrs: self.state.guest_vcpu_csrs,
+            interrupt_file: self
+                .interrupt_file
+                .map(|f| f.to_raw_index() as u32)
+                .unwrap_or(NO_INTERRUPT_FILE),
+        }
+    }
+
+    /// Restores this vCPU's architectural state from a snapshot previously taken with
+    /// `export_state()`. Callers must ensure the vCPU isn't running; `VmCpus::import_vcpu_state()`
+    /// enforces this via the existing `VmCpuStatus` locking.
+    pub fn import_state(&mut self, state: &VmCpuArchState) -> Result<()> {
+        // Validate the whole snapshot before mutating any of this vCPU's state, so a bad snapshot
+        // leaves the vCPU untouched rather than partially imported.
+        let interrupt_file = if state.interrupt_file == NO_INTERRUPT_FILE {
+            None
+        } else {
+            Some(
+                ImsicGuestId::from_raw_index(state.interrupt_file as usize)
+                    .ok_or(Error::InvalidImportedInterruptFile)?,
+            )
+        };
+
+        // Never trust an imported `hgatp`: it may name a PPN/VMID pair belonging to whatever VM
+        // the snapshot was taken from. Keep this vCPU's own `hgatp`, which was already derived
+        // from the importing VM's page table by `set_hgatp()`.
+        let hgatp = self.state.guest_vcpu_csrs.hgatp;
+        self.state.guest_regs = state.guest_regs;
+        self.state.guest_vcpu_csrs = state.guest_vcpu_csrs;
+        self.state.guest_vcpu_csrs.hgatp = hgatp;
+        self.interrupt_file = interrupt_file;
+
+        Ok(())
+    }
+
+    /// Completes an MMIO load previously reported via `VmCpuExit::Mmio`, writing the
+    /// (zero/sign-extended) `value` into the destination GPR and advancing `sepc` past the
+    /// faulting instruction.
+    pub fn complete_mmio_load(&mut self, value: u64) -> Result<()> {
+        let pending = self
+            .pending_mmio_load
+            .take()
+            .ok_or(Error::NoPendingMmioLoad)?;
+        let value = extend_mmio_value(value, pending.width, pending.sign_extend);
+        self.set_gpr(pending.gpr, value);
+        self.state.guest_regs.sepc += if pending.compressed { 2 } else { 4 };
+        Ok(())
+    }
+
+    /// Fetches the 32-bit instruction at the vCPU's current `sepc` from guest memory. Used as a
+    /// fallback when the hardware didn't provide a transformed instruction in `htinst`.
+    ///
+    /// `sepc` is only usable as-is (i.e. as a guest *physical* address) while the guest has no
+    /// first-stage translation of its own active. Once the guest enables `vsatp`, `sepc` is a
+    /// guest *virtual* address that would need to be walked through the guest's own page table
+    /// before the G-stage lookup `read_guest_memory` performs, which this vCPU has no way to do.
+    /// Since real guest kernels enable paging almost immediately, this fallback is only expected
+    /// to work very early in guest boot; callers must be prepared for
+    /// `Error::GuestVirtAddrTranslationUnsupported`.
+    fn fetch_faulting_insn<T: GuestStagePageTable>(
+        &self,
+        vm_pages: &VmPages<T, VmStateFinalized>,
+    ) -> Result<u32> {
+        // `vsatp` mode 0 is `Bare` (no translation); any other mode means `sepc` is a GVA we
+        // can't resolve without walking the guest's first-stage table.
+        if self.state.guest_vcpu_csrs.vsatp >> 60 != 0 {
+            return Err(Error::GuestVirtAddrTranslationUnsupported);
+        }
+        let addr = RawAddr::guest(self.state.guest_regs.sepc, self.guest_id);
+        let mut bytes = [0u8; 4];
+        self.read_guest_memory(vm_pages, addr, &mut bytes)
+            .map_err(|_| Error::GuestInstructionFetchFailed)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Decodes the load/store behind the current guest page fault, pulling the transformed
+    /// instruction out of `htinst` if the hardware provided one, or fetching and decoding the real
+    /// instruction from guest memory otherwise. Returns the `MmioAccess` to report to the host
+    /// and whether the faulting instruction was compressed.
+    fn decode_mmio_fault<T: GuestStagePageTable>(
+        &mut self,
+        vm_pages: &VmPages<T, VmStateFinalized>,
+    ) -> Result<(MmioAccess, bool)> {
+        let htinst = self.state.trap_csrs.htinst;
+        let (decoded, compressed) = if htinst != 0 {
+            // `htinst` is always in the standard 32-bit load/store encoding, even when bit 1
+            // (clear here, 0b11 -> 0b01 in bits 1:0) flags that the original instruction was a
+            // 16-bit RVC encoding -- that bit only controls how far to advance `sepc`, it doesn't
+            // mean `insn` itself is an RVC encoding. So this always goes through
+            // `decode_load_store_insn` with bit 1 restored, never
+            // `decode_compressed_load_store_insn`.
+            let insn = htinst as u32;
+            let compressed = insn & 0x2 == 0;
+            let decoded =
+                decode_load_store_insn(insn | 0x2).ok_or(Error::GuestInstructionFetchFailed)?;
+            (decoded, compressed)
+        } else {
+            // Here, by contrast, we fetched the real instruction bytes from guest memory, so a
+            // low-bits-!=-0b11 opcode genuinely is a 16-bit RVC encoding and must go through the
+            // RVC decoder instead.
+            let insn = self.fetch_faulting_insn(vm_pages)?;
+            let compressed = insn & 0x3 != 0x3;
+            let decoded = if compressed {
+                decode_compressed_load_store_insn(insn as u16)
+            } else {
+                decode_load_store_insn(insn)
+            }
+            .ok_or(Error::GuestInstructionFetchFailed)?;
+            (decoded, compressed)
+        };
+        let access = match decoded {
+            DecodedInsn::Load {
+                gpr,
+                width,
+                sign_extend,
+            } => MmioAccess {
+                op: MmioOperation::Load,
+                width,
+                sign_extend,
+                gpr,
+            },
+            DecodedInsn::Store { gpr, width } => MmioAccess {
+                op: MmioOperation::Store(self.state.guest_regs.gprs.reg(gpr)),
+                width,
+                sign_extend: false,
+                gpr,
+            },
+        };
+        Ok((access, compressed))
+    }
+
+    /// Fetches the raw instruction that caused a `VirtualInstruction` trap, preferring the
+    /// hardware-provided `htinst` (which, unlike for load/store faults, isn't a pseudo-instruction
+    /// here and can be used as-is) and falling back to fetching it from guest memory otherwise.
+    fn fetch_trapping_insn<T: GuestStagePageTable>(
+        &self,
+        vm_pages: &VmPages<T, VmStateFinalized>,
+    ) -> Result<u32> {
+        let htinst = self.state.trap_csrs.htinst;
+        if htinst != 0 {
+            Ok(htinst as u32)
+        } else {
+            self.fetch_faulting_insn(vm_pages)
+        }
+    }
+
     /// Runs this vCPU until it exits.
-    pub fn run_to_exit(&mut self) -> VmCpuExit {
+    pub fn run_to_exit<T: GuestStagePageTable>(
+        &mut self,
+        vm_pages: &VmPages<T, VmStateFinalized>,
+    ) -> VmCpuExit {
         // Load the vCPU CSRs. Safe as these don't take effect until V=1.
         CSR.hgatp.set(self.state.guest_vcpu_csrs.hgatp);
+        CSR.hvip.set(self.state.guest_vcpu_csrs.hvip);
         CSR.htimedelta.set(self.state.guest_vcpu_csrs.htimedelta);
         CSR.vsstatus.set(self.state.guest_vcpu_csrs.vsstatus);
         CSR.vsie.set(self.state.guest_vcpu_csrs.vsie);
@@ -310,12 +1029,24 @@ impl VmCpu {
 
         // TODO: Enforce that the vCPU has an assigned interrupt file before running.
 
+        let single_stepping = self.single_step;
+        if single_stepping {
+            self.arm_single_step_trigger();
+        }
+
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table.
             _run_guest(&mut self.state as *mut VmCpuState);
         }
 
+        // Disarm the trigger unconditionally if we armed it: most exits other than the trigger
+        // actually firing (e.g. `Ecall`, `Mmio`) leave it armed in hardware, since the guest never
+        // got to retire the stepped instruction. See `disarm_single_step_trigger()`.
+        if single_stepping {
+            self.disarm_single_step_trigger();
+        }
+
         // Save off the trap information.
         self.state.trap_csrs.scause = CSR.scause.get();
         self.state.trap_csrs.stval = CSR.stval.get();
@@ -324,6 +1055,7 @@ impl VmCpu {
 
         // Save the vCPU state.
         self.state.guest_vcpu_csrs.hgatp = CSR.hgatp.get();
+        self.state.guest_vcpu_csrs.hvip = CSR.hvip.get();
         self.state.guest_vcpu_csrs.htimedelta = CSR.htimedelta.get();
         self.state.guest_vcpu_csrs.vsstatus = CSR.vsstatus.get();
         self.state.guest_vcpu_csrs.vsie = CSR.vsie.get();
@@ -345,15 +1077,71 @@ impl VmCpu {
                 self.state.guest_regs.sepc += 4;
                 VmCpuExit::Ecall(sbi_msg)
             }
-            Trap::Exception(GuestInstructionPageFault)
-            | Trap::Exception(GuestLoadPageFault)
-            | Trap::Exception(GuestStorePageFault) => {
+            Trap::Exception(GuestInstructionPageFault) => {
                 let fault_addr = RawAddr::guest(
                     self.state.trap_csrs.htval << 2 | self.state.trap_csrs.stval & 0x03,
                     self.guest_id,
                 );
                 VmCpuExit::PageFault(fault_addr)
             }
+            Trap::Exception(GuestLoadPageFault) | Trap::Exception(GuestStorePageFault) => {
+                let fault_addr = RawAddr::guest(
+                    self.state.trap_csrs.htval << 2 | self.state.trap_csrs.stval & 0x03,
+                    self.guest_id,
+                );
+                match self.decode_mmio_fault(vm_pages) {
+                    Ok((access, compressed)) => {
+                        match access.op {
+                            MmioOperation::Load => {
+                                self.pending_mmio_load = Some(PendingMmioLoad {
+                                    gpr: access.gpr,
+                                    width: access.width,
+                                    sign_extend: access.sign_extend,
+                                    compressed,
+                                });
+                            }
+                            MmioOperation::Store(_) => {
+                                self.state.guest_regs.sepc += if compressed { 2 } else { 4 };
+                            }
+                        }
+                        VmCpuExit::Mmio {
+                            addr: fault_addr,
+                            access,
+                        }
+                    }
+                    Err(_) => VmCpuExit::Other(self.state.trap_csrs.clone()),
+                }
+            }
+            Trap::Exception(Breakpoint) => {
+                // A trigger-module single step disarms itself on fire; a software `ebreak`
+                // never armed one in the first place.
+                self.single_step = false;
+                VmCpuExit::DebugTrap {
+                    pc: self.state.guest_regs.sepc,
+                }
+            }
+            Trap::Exception(VirtualInstruction) => {
+                // `hstatus.VTW` is what causes `wfi` to trap here, but it's not the only thing
+                // that can: e.g. a guest read of `time`/`cycle`/`instret` also raises a
+                // `VirtualInstruction` exception if `hcounteren` (which we never configure) isn't
+                // set for that counter. Confirm the trapping instruction is actually `wfi` before
+                // treating it as one.
+                match self.fetch_trapping_insn(vm_pages) {
+                    Ok(INSN_WFI) => {
+                        self.state.guest_regs.sepc += 4;
+                        VmCpuExit::Wfi
+                    }
+                    _ => VmCpuExit::Other(self.state.trap_csrs.clone()),
+                }
+            }
+            Trap::Interrupt(Interrupt::SupervisorSoft) => {
+                // This is the IPI `VmCpus::kick()` sends to force us out of guest execution;
+                // clear it so we don't immediately re-trap the next time we're entered.
+                let mut sip = LocalRegisterCopy::<u64, sip::Register>::new(CSR.sip.get());
+                sip.modify(sip::ssoft.val(0));
+                CSR.sip.set(sip.get());
+                VmCpuExit::Interrupted
+            }
             _ => VmCpuExit::Other(self.state.trap_csrs.clone()),
         }
     }
@@ -368,11 +1156,17 @@ pub enum VmCpuStatus {
     Available,
     /// The vCPU has been claimed exclusively for running on a (physical) CPU.
     Running,
+    /// The vCPU is claimed by a (physical) CPU but is blocked waiting for an interrupt (e.g. in
+    /// `VmCpuExit::Wfi`) instead of making guest progress.
+    Blocked,
 }
 
 struct VmCpusInner {
     // Locking: status must be locked before vcpu.
     status: RwLock<VmCpuStatus>,
+    // The physical hart currently running this vCPU. Only meaningful while `status` is
+    // `Running` or `Blocked`; set by `take_vcpu()` and cleared when the `RunningVmCpu` is dropped.
+    running_hart: RwLock<Option<u64>>,
     vcpu: Mutex<VmCpu>,
 }
 
@@ -407,15 +1201,47 @@ impl<'a> Deref for RunningVmCpu<'a> {
     }
 }
 
+impl<'a> RunningVmCpu<'a> {
+    /// Marks this vCPU as blocked, e.g. because it's waiting in `VmCpuExit::Wfi` for an
+    /// interrupt. Other physical CPUs can observe the `Blocked` status instead of `Running` to
+    /// tell that this vCPU isn't making progress.
+    pub fn block(&self) {
+        let entry = self.parent.inner.get(self.id as usize).unwrap();
+        let mut status = entry.status.write();
+        assert_eq!(*status, VmCpuStatus::Running);
+        *status = VmCpuStatus::Blocked;
+    }
+
+    /// Marks this vCPU as running again after a prior `block()`, e.g. because an interrupt
+    /// became pending for it.
+    pub fn unblock(&self) {
+        let entry = self.parent.inner.get(self.id as usize).unwrap();
+        let mut status = entry.status.write();
+        assert_eq!(*status, VmCpuStatus::Blocked);
+        *status = VmCpuStatus::Running;
+    }
+}
+
 impl<'a> Drop for RunningVmCpu<'a> {
     fn drop(&mut self) {
         let entry = self.parent.inner.get(self.id as usize).unwrap();
         let mut status = entry.status.write();
-        assert_eq!(*status, VmCpuStatus::Running);
+        assert!(matches!(
+            *status,
+            VmCpuStatus::Running | VmCpuStatus::Blocked
+        ));
         *status = VmCpuStatus::Available;
+        *entry.running_hart.write() = None;
     }
 }
 
+/// Sends an IPI to `hart_id` via the SBI IPI extension, used by `VmCpus::kick()` to force a
+/// running vCPU's physical CPU to re-enter the hypervisor.
+fn send_ipi_to_hart(hart_id: u64) -> Result<()> {
+    sbi::ipi::send_ipi(1u64 << hart_id, 0).map_err(|_| Error::KickFailed)?;
+    Ok(())
+}
+
 /// The set of vCPUs in a VM.
 pub struct VmCpus {
     inner: PageVec<VmCpusInner>,
@@ -431,6 +1257,7 @@ impl VmCpus {
         for _ in 0..MAX_CPUS {
             let entry = VmCpusInner {
                 status: RwLock::new(VmCpuStatus::NotPresent),
+                running_hart: RwLock::new(None),
                 vcpu: Mutex::new(VmCpu::new(guest_id)),
             };
             inner.push(entry);
@@ -467,22 +1294,334 @@ impl VmCpus {
         }
     }
 
-    /// Takes exclusive ownership of the vCPU with `vcpu_id`, marking it as running. The vCPU is
-    /// returned to the "Available" state when the returned `RunningVmCpu` is dropped.
-    pub fn take_vcpu(&self, vcpu_id: u64) -> Result<RunningVmCpu> {
+    /// Returns a reference to the vCPU with `vcpu_id` for read-only inspection (e.g. a core dump),
+    /// allowing `Blocked` vCPUs in addition to `Available` ones since neither is concurrently
+    /// mutated by `_run_guest`. Still fails with `Error::VmCpuRunning` if the vCPU is actually
+    /// running on a physical CPU right now, since its state isn't safe to read until then.
+    pub fn inspect_vcpu(&self, vcpu_id: u64) -> Result<IdleVmCpu> {
+        let entry = self.inner.get(vcpu_id as usize).ok_or(Error::BadCpuId)?;
+        let status = entry.status.read();
+        match *status {
+            VmCpuStatus::Available | VmCpuStatus::Blocked => Ok(IdleVmCpu {
+                _status: status,
+                vcpu: &entry.vcpu,
+            }),
+            VmCpuStatus::Running => Err(Error::VmCpuRunning),
+            VmCpuStatus::NotPresent => Err(Error::VmCpuNotFound),
+        }
+    }
+
+    /// Exports the architectural state of the vCPU with `vcpu_id`, for snapshot/migration. Fails
+    /// with `Error::VmCpuRunning` if the vCPU is currently running.
+    pub fn export_vcpu_state(&self, vcpu_id: u64) -> Result<VmCpuArchState> {
+        let vcpu = self.get_vcpu(vcpu_id)?;
+        Ok(vcpu.lock().export_state())
+    }
+
+    /// Imports a previously-exported architectural state into the vCPU with `vcpu_id`. Fails with
+    /// `Error::VmCpuRunning` if the vCPU is currently running.
+    pub fn import_vcpu_state(&self, vcpu_id: u64, state: &VmCpuArchState) -> Result<()> {
+        let vcpu = self.get_vcpu(vcpu_id)?;
+        vcpu.lock().import_state(state)
+    }
+
+    /// Takes exclusive ownership of the vCPU with `vcpu_id`, marking it as running on physical
+    /// CPU `cpu_id`. The vCPU is returned to the "Available" state when the returned
+    /// `RunningVmCpu` is dropped.
+    pub fn take_vcpu(&self, vcpu_id: u64, cpu_id: u64) -> Result<RunningVmCpu> {
         let entry = self.inner.get(vcpu_id as usize).ok_or(Error::BadCpuId)?;
         let mut status = entry.status.write();
         match *status {
             VmCpuStatus::Available => {
                 *status = VmCpuStatus::Running;
+                *entry.running_hart.write() = Some(cpu_id);
                 Ok(RunningVmCpu {
                     parent: self,
                     vcpu: &entry.vcpu,
                     id: vcpu_id,
                 })
             }
-            VmCpuStatus::Running => Err(Error::VmCpuRunning),
+            VmCpuStatus::Running | VmCpuStatus::Blocked => Err(Error::VmCpuRunning),
             VmCpuStatus::NotPresent => Err(Error::VmCpuNotFound),
         }
     }
-}
\ No newline at end of file
+
+    /// Forces the vCPU with `vcpu_id`, which must currently be `Running` or `Blocked`, out of
+    /// guest execution by sending an IPI to the physical CPU it's running on. `run_to_exit` on
+    /// that CPU sees the IPI as an immediate HS-level trap and returns `VmCpuExit::Interrupted` so
+    /// the scheduler can re-check for pending work (newly injected interrupts, stop/migration
+    /// requests, etc.) without losing guest progress. `Blocked` is accepted as well as `Running`
+    /// since a vCPU parked in `VmCpuExit::Wfi` still claims its physical CPU (`block()`/
+    /// `unblock()` don't touch `running_hart`) -- this is in fact the main case `kick()` needs to
+    /// handle, since it's how a remote `inject_interrupt()` call forces the target hart back out
+    /// of `wfi` to deliver the newly-pending interrupt.
+    pub fn kick(&self, vcpu_id: u64) -> Result<()> {
+        let entry = self.inner.get(vcpu_id as usize).ok_or(Error::BadCpuId)?;
+        let status = entry.status.read();
+        if !matches!(*status, VmCpuStatus::Running | VmCpuStatus::Blocked) {
+            return Err(Error::VcpuNotRunning);
+        }
+        let hart = entry.running_hart.read().ok_or(Error::VcpuNotRunning)?;
+        send_ipi_to_hart(hart)
+    }
+
+    /// Returns the ids of all vCPUs that have been added to this VM (in any status).
+    pub fn present_vcpu_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.inner.len() as u64).filter(move |&id| {
+            self.inner
+                .get(id as usize)
+                .map(|entry| *entry.status.read() != VmCpuStatus::NotPresent)
+                .unwrap_or(false)
+        })
+    }
+}
+
+// ELF64 types and constants used by `write_vm_coredump()`. See the System V ABI and the RISC-V
+// ELF psABI for the field layouts.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_R: u32 = 4;
+const PF_W: u32 = 2;
+const NT_PRSTATUS: u32 = 1;
+const CORE_NOTE_NAME: &[u8] = b"CORE\0";
+
+fn elf_ident() -> [u8; 16] {
+    let mut ident = [0u8; 16];
+    ident[0..4].copy_from_slice(b"\x7fELF");
+    ident[4] = ELFCLASS64;
+    ident[5] = ELFDATA2LSB;
+    ident[6] = EV_CURRENT;
+    ident
+}
+
+fn round_up4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// SAFETY: all of the `#[repr(C)]` structs passed to this function are plain old data with no
+// padding-sensitive invariants, so reinterpreting them as bytes is sound.
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod coredump_tests {
+    use super::*;
+
+    #[test]
+    fn round_up4_rounds_to_next_multiple_of_four() {
+        assert_eq!(round_up4(0), 0);
+        assert_eq!(round_up4(1), 4);
+        assert_eq!(round_up4(4), 4);
+        assert_eq!(round_up4(5), 8);
+        assert_eq!(round_up4(CORE_NOTE_NAME.len()), 8);
+    }
+
+    #[test]
+    fn elf_ident_has_elf_magic_and_class() {
+        let ident = elf_ident();
+        assert_eq!(&ident[0..4], b"\x7fELF");
+        assert_eq!(ident[4], ELFCLASS64);
+        assert_eq!(ident[5], ELFDATA2LSB);
+        assert_eq!(ident[6], EV_CURRENT);
+    }
+
+    #[test]
+    fn note_and_phdr_offsets_are_contiguous() {
+        // Mirrors the layout computed by `write_vm_coredump`: ehdr, then one phdr per PT_NOTE/
+        // PT_LOAD segment, then the notes themselves, then region data. Each offset must start
+        // exactly where the previous structure ended, with no gaps or overlaps.
+        let num_vcpus = 2usize;
+        let num_regions = 3usize;
+        let note_len =
+            size_of::<Elf64Nhdr>() + round_up4(CORE_NOTE_NAME.len()) + size_of::<PrStatus>();
+        let notes_size = note_len * num_vcpus;
+
+        let num_phdrs = 1 + num_regions;
+        let phoff = size_of::<Elf64Ehdr>() as u64;
+        let notes_offset = phoff + (num_phdrs * size_of::<Elf64Phdr>()) as u64;
+        let data_offset = notes_offset + notes_size as u64;
+
+        assert_eq!(phoff, size_of::<Elf64Ehdr>() as u64);
+        assert_eq!(
+            notes_offset,
+            phoff + (num_phdrs * size_of::<Elf64Phdr>()) as u64
+        );
+        assert_eq!(data_offset, notes_offset + notes_size as u64);
+        assert!(data_offset > notes_offset);
+    }
+}
+
+/// Writes an ELF64 core file capturing the register state of every present vCPU in `vcpus` plus
+/// the guest memory described by `regions`, for post-mortem debugging of a guest that crashed or
+/// halted unexpectedly (e.g. one that returned `VmCpuExit::Other`).
+///
+/// If `metadata_only` is set, `regions` are still described by `PT_LOAD` program headers but
+/// their contents are omitted from the file, which is the appropriate mode for an
+/// attested/confidential VM whose memory shouldn't be copied out to the host's core file.
+/// `scratch` is used as a chunking buffer when copying region contents and must be non-empty.
+///
+/// Fails with `Error::VmCpuRunning` if any present vCPU is actually running on a physical CPU at
+/// the time of the call; `Blocked` vCPUs (e.g. idling in `VmCpuExit::Wfi`) are dumped normally,
+/// since only a vCPU that's `Running` has state that's concurrently mutated by `_run_guest`. This
+/// check, like the set of vCPUs to dump, is resolved once up front, before anything is written to
+/// `sink`, so a running vCPU never leaves a partial core file behind. An empty `scratch` (when
+/// `metadata_only` isn't set) or a failure to copy region contents is only detected after the
+/// headers and notes have been written to `sink`.
+pub fn write_vm_coredump<T: GuestStagePageTable, W: FnMut(&[u8])>(
+    vcpus: &VmCpus,
+    vm_pages: &VmPages<T, VmStateFinalized>,
+    regions: &[CoreDumpRegion],
+    metadata_only: bool,
+    scratch: &mut [u8],
+    mut sink: W,
+) -> Result<()> {
+    // Snapshot the present vCPU ids exactly once, and hold each one's `IdleVmCpu` read lock for
+    // the rest of this function. `present_vcpu_ids()` reflects live `VmCpus` state; calling it
+    // again later (as the note-emission loop below used to) to decide what to write is a TOCTOU
+    // against a vCPU being added in between, which would desync the PT_NOTE segment size declared
+    // in the phdrs we've already committed to `sink` by that point. Holding the locks (rather
+    // than just checking once up front) additionally guarantees none of them can transition to
+    // `Running` -- and so have their state concurrently mutated by `_run_guest` -- while we're
+    // still emitting notes for them; `Error::VmCpuRunning` is returned immediately, before
+    // anything is written to `sink`, if one already is.
+    let mut vcpu_ids = [0u64; MAX_CPUS];
+    let mut num_vcpus = 0;
+    for id in vcpus.present_vcpu_ids() {
+        vcpu_ids[num_vcpus] = id;
+        num_vcpus += 1;
+    }
+    let vcpu_ids = &vcpu_ids[..num_vcpus];
+
+    let mut held: [Option<IdleVmCpu>; MAX_CPUS] = core::array::from_fn(|_| None);
+    for (slot, &id) in held.iter_mut().zip(vcpu_ids) {
+        *slot = Some(vcpus.inspect_vcpu(id)?);
+    }
+
+    let note_len = size_of::<Elf64Nhdr>() + round_up4(CORE_NOTE_NAME.len()) + size_of::<PrStatus>();
+    let notes_size = note_len * num_vcpus;
+
+    let num_phdrs = 1 + regions.len();
+    let phoff = size_of::<Elf64Ehdr>() as u64;
+    let notes_offset = phoff + (num_phdrs * size_of::<Elf64Phdr>()) as u64;
+    let mut data_offset = notes_offset + notes_size as u64;
+
+    let ehdr = Elf64Ehdr {
+        e_ident: elf_ident(),
+        e_type: ET_CORE,
+        e_machine: EM_RISCV,
+        e_version: EV_CURRENT as u32,
+        e_phoff: phoff,
+        e_ehsize: size_of::<Elf64Ehdr>() as u16,
+        e_phentsize: size_of::<Elf64Phdr>() as u16,
+        e_phnum: num_phdrs as u16,
+        ..Default::default()
+    };
+    sink(as_bytes(&ehdr));
+
+    let note_phdr = Elf64Phdr {
+        p_type: PT_NOTE,
+        p_offset: notes_offset,
+        p_filesz: notes_size as u64,
+        p_align: 4,
+        ..Default::default()
+    };
+    sink(as_bytes(&note_phdr));
+
+    for region in regions {
+        let filesz = if metadata_only { 0 } else { region.len };
+        let phdr = Elf64Phdr {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_W,
+            p_offset: data_offset,
+            p_vaddr: region.addr.bits(),
+            p_paddr: region.addr.bits(),
+            p_filesz: filesz,
+            p_memsz: region.len,
+            p_align: 0x1000,
+        };
+        sink(as_bytes(&phdr));
+        data_offset += filesz;
+    }
+
+    for vcpu in held[..num_vcpus].iter().flatten() {
+        let prstatus = vcpu.lock().prstatus_note();
+        let nhdr = Elf64Nhdr {
+            n_namesz: CORE_NOTE_NAME.len() as u32,
+            n_descsz: size_of::<PrStatus>() as u32,
+            n_type: NT_PRSTATUS,
+        };
+        sink(as_bytes(&nhdr));
+        sink(CORE_NOTE_NAME);
+        sink(&[0u8; 4][..round_up4(CORE_NOTE_NAME.len()) - CORE_NOTE_NAME.len()]);
+        sink(as_bytes(&prstatus));
+    }
+
+    if metadata_only {
+        return Ok(());
+    }
+    if scratch.is_empty() {
+        return Err(Error::GuestMemoryAccessFailed);
+    }
+    for region in regions {
+        let mut remaining = region.len;
+        let mut addr = region.addr.bits();
+        while remaining > 0 {
+            let chunk = remaining.min(scratch.len() as u64) as usize;
+            let buf = &mut scratch[..chunk];
+            vm_pages
+                .copy_from_guest(RawAddr::guest(addr, region.owner), buf)
+                .map_err(|_| Error::GuestMemoryAccessFailed)?;
+            sink(buf);
+            addr += chunk as u64;
+            remaining -= chunk as u64;
+        }
+    }
+
+    Ok(())
+}